@@ -1,5 +1,12 @@
 mod libc_wrapper;
+#[cfg(feature = "9p")]
+mod ninep;
 mod tagfs;
+mod vfs;
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "webdav")]
+mod webdav;
 
 use chrono::Local;
 use std::ffi::OsStr;
@@ -37,13 +44,42 @@ fn main() -> io::Result<()> {
     log::set_max_level(log::LevelFilter::Debug);
 
     let args: Vec<String> = env::args().collect();
-
-    let tag_fs = TagFS::new(&args[1]);
+    let root = &args[1];
 
     debug!("Hi");
     trace!("Hello, world!");
     info!("bye");
 
+    // Live-watching is only wired up for the webdav/9p frontends below, which
+    // already share an `Arc<Vfs>`; the FUSE path owns its `Vfs` outright. If
+    // neither is enabled, nothing ever reads this.
+    #[cfg(feature = "watch")]
+    #[cfg_attr(not(any(feature = "webdav", feature = "9p")), allow(unused_variables))]
+    let watch_requested = args.iter().any(|a| a == "--watch");
+
+    #[cfg(feature = "webdav")]
+    if args.get(2).map(String::as_str) == Some("--webdav") {
+        let addr = args.get(3).expect("--webdav requires an address");
+        let vfs = std::sync::Arc::new(vfs::Vfs::new(root));
+        #[cfg(feature = "watch")]
+        if watch_requested {
+            watch::spawn(vfs.clone(), root);
+        }
+        return webdav::serve(vfs, addr);
+    }
+
+    #[cfg(feature = "9p")]
+    if args.get(2).map(String::as_str) == Some("--9p") {
+        let addr = args.get(3).expect("--9p requires an address");
+        let vfs = std::sync::Arc::new(vfs::Vfs::new(root));
+        #[cfg(feature = "watch")]
+        if watch_requested {
+            watch::spawn(vfs.clone(), root);
+        }
+        return ninep::serve(vfs, addr);
+    }
+
+    let tag_fs = TagFS::new(root);
     let fuse_args: Vec<&OsStr> = vec![OsStr::new("-o"), OsStr::new("auto_unmount")];
 
     fuse_mt::mount(fuse_mt::FuseMT::new(tag_fs, 1), &args[2], &fuse_args).unwrap();
@@ -0,0 +1,231 @@
+//! Serve the tag tree over 9P2000.L, so it can be mounted by a 9P client (a VM
+//! guest's `v9fs`, a network mount) without a FUSE kernel module. `NinePFs` is a
+//! thin adapter over `Vfs`, the same tag-navigation core the FUSE and WebDAV
+//! frontends use.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use rs9p::srv::{srv_async, Fid, Filesystem};
+use rs9p::{
+    Data, DirEntry, DirEntryData, Error as NineError, Fcall, GetattrMask, Qid, QidType,
+    Result as NineResult, Stat, Time,
+};
+
+use crate::vfs::{Vfs, VfsAttr, VfsKind};
+
+/// A node's qid path is a stable hash of the tag-path-plus-name it was walked to,
+/// so the same node gets the same qid across walks without a separate inode table.
+fn qid_for(path: &Path, attr: &VfsAttr) -> Qid {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    Qid {
+        typ: if attr.kind == VfsKind::Directory {
+            QidType::DIR
+        } else {
+            QidType::empty()
+        },
+        version: 0,
+        path: hasher.finish(),
+    }
+}
+
+fn to_time(t: SystemTime) -> Time {
+    let since_epoch = t
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    Time {
+        sec: since_epoch.as_secs(),
+        nsec: u64::from(since_epoch.subsec_nanos()),
+    }
+}
+
+fn to_stat(attr: &VfsAttr) -> Stat {
+    Stat {
+        mode: match attr.kind {
+            VfsKind::Directory => libc::S_IFDIR | u32::from(attr.perm),
+            _ => u32::from(attr.perm),
+        },
+        uid: attr.uid,
+        gid: attr.gid,
+        nlink: attr.nlink as u64,
+        rdev: u64::from(attr.rdev),
+        size: attr.size,
+        blksize: 4096,
+        blocks: attr.blocks,
+        atime: to_time(attr.atime),
+        mtime: to_time(attr.mtime),
+        ctime: to_time(attr.ctime),
+    }
+}
+
+/// Per-fid state: the tag path it's currently walked to. `Fid::aux` is a plain
+/// field with no interior mutability of its own, so the path lives behind a
+/// `Mutex` to let `rwalk`/`rattach` update it through the shared `&Fid<...>`
+/// the `Filesystem` trait hands them.
+type NinePFid = Mutex<PathBuf>;
+
+/// A thin 9P2000.L adapter over `Vfs`: `Twalk` narrows the tag path one
+/// component at a time exactly like FUSE `lookup`/`readdir` does, and
+/// `Tgetattr`/`Treaddir`/`Tread` all delegate straight to the shared core.
+#[derive(Clone)]
+pub struct NinePFs {
+    vfs: Arc<Vfs>,
+}
+
+impl NinePFs {
+    pub fn new(vfs: Arc<Vfs>) -> NinePFs {
+        NinePFs { vfs }
+    }
+}
+
+#[async_trait]
+impl Filesystem for NinePFs {
+    type Fid = NinePFid;
+
+    async fn rattach(
+        &self,
+        fid: &Fid<Self::Fid>,
+        _afid: Option<&Fid<Self::Fid>>,
+        _uname: &str,
+        _aname: &str,
+        _n_uname: u32,
+    ) -> NineResult<Fcall> {
+        let path = PathBuf::from("/");
+        let attr = self.vfs.attr(&path)?;
+        let qid = qid_for(&path, &attr);
+        *fid.aux.lock().unwrap() = path;
+        Ok(Fcall::Rattach { qid })
+    }
+
+    async fn rwalk(
+        &self,
+        fid: &Fid<Self::Fid>,
+        newfid: &Fid<Self::Fid>,
+        wnames: &[String],
+    ) -> NineResult<Fcall> {
+        // `Vfs` only understands `Component::Normal` segments, so maintain an
+        // explicit stack here rather than a bare `PathBuf::push`: pushing ".."
+        // onto a `PathBuf` just appends a literal `ParentDir` component instead
+        // of popping the last one, which `Vfs` would then silently drop and
+        // leave the tag query un-narrowed.
+        let mut stack: Vec<std::ffi::OsString> = fid
+            .aux
+            .lock()
+            .unwrap()
+            .components()
+            .filter_map(|c| match c {
+                std::path::Component::Normal(name) => Some(name.to_os_string()),
+                _ => None,
+            })
+            .collect();
+        let mut qids = Vec::with_capacity(wnames.len());
+        for name in wnames {
+            if name == ".." {
+                stack.pop();
+            } else if name != "." {
+                stack.push(name.into());
+            }
+            let path: PathBuf = std::iter::once(std::ffi::OsString::from("/"))
+                .chain(stack.iter().cloned())
+                .collect();
+            let attr = match self.vfs.attr(&path) {
+                Ok(attr) => attr,
+                Err(_) => break,
+            };
+            qids.push(qid_for(&path, &attr));
+        }
+        if qids.len() == wnames.len() {
+            let path: PathBuf = std::iter::once(std::ffi::OsString::from("/"))
+                .chain(stack)
+                .collect();
+            *newfid.aux.lock().unwrap() = path;
+        }
+        Ok(Fcall::Rwalk { wqids: qids })
+    }
+
+    async fn rgetattr(&self, fid: &Fid<Self::Fid>, _req_mask: GetattrMask) -> NineResult<Fcall> {
+        let path = fid.aux.lock().unwrap().clone();
+        let attr = self.vfs.attr(&path)?;
+        Ok(Fcall::Rgetattr {
+            valid: GetattrMask::ALL,
+            qid: qid_for(&path, &attr),
+            stat: to_stat(&attr),
+        })
+    }
+
+    async fn rreaddir(&self, fid: &Fid<Self::Fid>, offset: u64, _count: u32) -> NineResult<Fcall> {
+        // The whole listing is produced in one response; a non-zero offset means
+        // the client already consumed it, so signal end-of-directory with an
+        // empty result rather than re-walking `Vfs` for a cursor it doesn't keep.
+        if offset != 0 {
+            return Ok(Fcall::Rreaddir {
+                data: DirEntryData::new(),
+            });
+        }
+        let path = fid.aux.lock().unwrap().clone();
+        let mut data = DirEntryData::new();
+        for (index, entry) in self.vfs.readdir(&path).into_iter().enumerate() {
+            let child = path.join(&entry.name);
+            let attr = self.vfs.attr(&child).unwrap_or_else(|_| VfsAttr {
+                size: 0,
+                blocks: 0,
+                atime: SystemTime::UNIX_EPOCH,
+                mtime: SystemTime::UNIX_EPOCH,
+                ctime: SystemTime::UNIX_EPOCH,
+                kind: entry.kind,
+                perm: 0o0644,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+            });
+            data.push(DirEntry {
+                qid: qid_for(&child, &attr),
+                offset: (index + 1) as u64,
+                typ: 0,
+                name: entry.name.to_string_lossy().into_owned(),
+            });
+        }
+        Ok(Fcall::Rreaddir { data })
+    }
+
+    async fn rlopen(&self, fid: &Fid<Self::Fid>, _flags: u32) -> NineResult<Fcall> {
+        let path = fid.aux.lock().unwrap().clone();
+        let attr = self.vfs.attr(&path)?;
+        Ok(Fcall::Rlopen {
+            qid: qid_for(&path, &attr),
+            iounit: 0,
+        })
+    }
+
+    async fn rread(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> NineResult<Fcall> {
+        let path = fid.aux.lock().unwrap().clone();
+        let data = self.vfs.read_at(&path, offset, count)?;
+        Ok(Fcall::Rread { data: Data(data) })
+    }
+
+    async fn rclunk(&self, _fid: &Fid<Self::Fid>) -> NineResult<Fcall> {
+        Ok(Fcall::Rclunk)
+    }
+}
+
+/// Serve `vfs` over 9P2000.L at `addr`, e.g. `127.0.0.1:5640`. Blocks the calling
+/// thread for the lifetime of the server.
+pub fn serve(vfs: Arc<Vfs>, addr: &str) -> io::Result<()> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "expected host:port"))?;
+    let plan9_addr = format!("tcp!{}!{}", host, port);
+
+    info!("9p: listening on {}", addr);
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime
+        .block_on(srv_async(NinePFs::new(vfs), &plan9_addr))
+        .map_err(|e: NineError| io::Error::other(e.to_string()))
+}
@@ -1,197 +1,73 @@
 use fuse_mt::{
-    DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultEmpty, ResultEntry,
-    ResultOpen, ResultReaddir, ResultXattr, Xattr,
+    CallbackResult, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultEmpty,
+    ResultEntry, ResultOpen, ResultReaddir, ResultSlice, ResultXattr, Xattr,
 };
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
-use std::fs;
-use std::io;
-use std::os::unix::fs::MetadataExt;
-use std::path::{Component::Normal, Path, PathBuf};
-use std::time::{Duration, SystemTime};
-use walkdir::WalkDir;
+use std::path::Path;
+use std::time::Duration;
 
-use crate::libc_wrapper;
+use crate::vfs::{Vfs, VfsAttr, VfsKind};
 
 const TTL: Duration = Duration::from_secs(1);
 
-#[derive(Debug)]
-struct TagFSEntry {
-    name: OsString,
-    absolute: PathBuf,
-    size: u64,
-    tags: HashSet<OsString>,
-}
-
-impl TagFSEntry {
-    pub fn new(root: &str, entry: &walkdir::DirEntry, meta: &std::fs::Metadata) -> TagFSEntry {
-        let components: HashSet<_> = entry
-            .path()
-            .parent()
-            .unwrap()
-            .strip_prefix(root)
-            .unwrap()
-            .components()
-            .map(|comp| comp.as_os_str().to_owned())
-            .collect();
-        let absolute = std::env::current_dir()
-            .unwrap()
-            .as_path()
-            .join(entry.path())
-            .canonicalize()
-            .unwrap();
-        TagFSEntry {
-            name: entry.file_name().to_owned(),
-            absolute,
-            size: meta.size(),
-            tags: components,
-        }
-    }
-
-    fn stat(&self) -> io::Result<FileAttr> {
-        let stat = libc_wrapper::lstat(&self.absolute)?;
-        Ok(Self::stat_to_fuse(stat))
-    }
-    fn stat_to_fuse(stat: libc::stat) -> FileAttr {
-        // st_mode encodes both the kind and the permissions
-        let kind = TagFS::mode_to_filetype(stat.st_mode);
-        let perm = (stat.st_mode & 0o7777) as u16;
-
-        FileAttr {
-            size: stat.st_size as u64,
-            blocks: stat.st_blocks as u64,
-            atime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_atime as u64)
-                + Duration::from_nanos(stat.st_atime_nsec as u64),
-            mtime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_mtime as u64)
-                + Duration::from_nanos(stat.st_mtime_nsec as u64),
-            ctime: SystemTime::UNIX_EPOCH
-                + Duration::from_secs(stat.st_ctime as u64)
-                + Duration::from_nanos(stat.st_ctime_nsec as u64),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind,
-            perm,
-            nlink: stat.st_nlink as u32,
-            uid: stat.st_uid,
-            gid: stat.st_gid,
-            rdev: stat.st_rdev as u32,
-            flags: 0,
-        }
-    }
-}
+/// xattr namespace under which a single tag is added/removed via `setxattr`/
+/// `removexattr`, e.g. `user.tagfs.tag.foo`.
+const TAG_XATTR_PREFIX: &str = "user.tagfs.tag.";
 
 pub struct TagFS {
-    root: String,
-    tags: HashSet<OsString>,
-    entries: Vec<TagFSEntry>,
+    vfs: Vfs,
     attrs: HashMap<&'static str, &'static str>,
 }
 
 impl TagFS {
     pub fn new(root: &str) -> TagFS {
-        let entries = scan(root);
-        debug!("{:?}", entries);
         TagFS {
-            root: root.to_string(),
-            tags: entries
-                .iter()
-                .flat_map(|tag_entry| tag_entry.tags.clone())
-                .collect(),
-            entries,
+            vfs: Vfs::new(root),
             attrs: vec![("user.tagfs.strategy", "0"), ("user.tagfs.depth", "1")]
                 .into_iter()
                 .collect(),
         }
     }
 
-    fn mode_to_filetype(mode: libc::mode_t) -> FileType {
-        match mode & libc::S_IFMT {
-            libc::S_IFDIR => FileType::Directory,
-            libc::S_IFREG => FileType::RegularFile,
-            libc::S_IFLNK => FileType::Symlink,
-            libc::S_IFBLK => FileType::BlockDevice,
-            libc::S_IFCHR => FileType::CharDevice,
-            libc::S_IFIFO => FileType::NamedPipe,
-            libc::S_IFSOCK => FileType::Socket,
-            _ => {
-                panic!("unknown file type");
-            }
+    fn kind_to_fuse(kind: VfsKind) -> FileType {
+        match kind {
+            VfsKind::Directory => FileType::Directory,
+            VfsKind::RegularFile => FileType::RegularFile,
+            VfsKind::Symlink => FileType::Symlink,
+            VfsKind::BlockDevice => FileType::BlockDevice,
+            VfsKind::CharDevice => FileType::CharDevice,
+            VfsKind::NamedPipe => FileType::NamedPipe,
+            VfsKind::Socket => FileType::Socket,
         }
     }
 
-    fn stat_to_fuse() -> FileAttr {
+    fn attr_to_fuse(attr: VfsAttr) -> FileAttr {
         FileAttr {
-            size: 0,
-            blocks: 0,
-            atime: SystemTime::UNIX_EPOCH,
-            mtime: SystemTime::UNIX_EPOCH,
-            ctime: SystemTime::UNIX_EPOCH,
-            crtime: SystemTime::UNIX_EPOCH,
-            kind: FileType::Directory,
-            perm: 0o0755,
-            nlink: 1,
-            uid: 0,
-            gid: 0,
-            rdev: 0,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+            crtime: std::time::SystemTime::UNIX_EPOCH,
+            kind: Self::kind_to_fuse(attr.kind),
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
             flags: 0,
         }
     }
 
-    fn tags(path: &Path) -> Option<Vec<OsString>> {
-        Some(
-            path.parent()?
-                .components()
-                .map(|comp| comp.as_os_str().to_owned())
-                .filter(|comp| comp != "/")
-                .collect(),
-        )
+    /// Extract the tag name from a `user.tagfs.tag.<name>` xattr name.
+    fn tag_from_xattr_name(name: &OsStr) -> Option<OsString> {
+        name.to_string_lossy()
+            .strip_prefix(TAG_XATTR_PREFIX)
+            .map(OsString::from)
     }
 }
 
-fn info(entry: &walkdir::DirEntry, meta: &std::fs::Metadata) {
-    let dev_id = meta.dev();
-    let inode = meta.ino();
-    println!(
-        "{} {} {} {:o} {:?} {} {} (@ {})",
-        dev_id,
-        inode,
-        entry.path().display(),
-        meta.mode(),
-        meta.is_dir(),
-        meta.is_file(),
-        meta.size(),
-        std::env::current_dir()
-            .unwrap()
-            .as_path()
-            .join(entry.path())
-            .canonicalize()
-            .unwrap()
-            .display()
-    );
-}
-
-fn process(root: &str, entry: &walkdir::DirEntry) -> Option<TagFSEntry> {
-    let meta = match fs::metadata(entry.path()) {
-        Ok(meta) => meta,
-        _ => return None,
-    };
-    //info(&entry, &meta);
-    if meta.is_file() {
-        if let Some(_p) = entry.path().parent() {
-            return Some(TagFSEntry::new(root, entry, &meta));
-        }
-    };
-    None
-}
-
-fn scan(root: &str) -> Vec<TagFSEntry> {
-    WalkDir::new(root)
-        .into_iter()
-        .filter_map(|entry| entry.ok().and_then(|entry| process(root, &entry)))
-        .collect()
-}
-
 impl FilesystemMT for TagFS {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         debug!("init");
@@ -204,96 +80,28 @@ impl FilesystemMT for TagFS {
 
     fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
         debug!("getattr: {:?} {:?}", path, fh);
-
-        debug!("TODO: lookup {:?} {:?}", path, Self::tags(path));
-        Ok((TTL, TagFS::stat_to_fuse()))
-        /*
-
-        if let Some(fh) = fh {
-            match libc_wrappers::fstat(fh) {
-                Ok(stat) => Ok((TTL, stat_to_fuse(stat))),
-                Err(e) => Err(e)
-            }
-        } else {
-            match self.stat_real(path) {
-                Ok(attr) => Ok((TTL, attr)),
-                Err(e) => Err(e.raw_os_error().unwrap())
-            }
-        }
-        */
+        self.vfs
+            .attr(path)
+            .map(|attr| (TTL, Self::attr_to_fuse(attr)))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
     }
 
     fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
         debug!("opendir: {:?} (flags = {:#o})", path, _flags);
-        //let real = self.real_path(path);
         Ok((0, 0))
     }
 
     fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
         debug!("readdir: {:?}", path);
-        let cur_tags: HashSet<OsString> = path
-            .components()
-            .filter_map(|c| match c {
-                Normal(t) => Some(t.to_os_string()),
-                _ => None,
+        let entries = self
+            .vfs
+            .readdir(path)
+            .into_iter()
+            .map(|entry| DirectoryEntry {
+                name: entry.name,
+                kind: Self::kind_to_fuse(entry.kind),
             })
-            .collect();
-        debug!("components: {:?}", cur_tags);
-
-        let mut entries: Vec<DirectoryEntry> = vec![];
-        for tag in &self.tags {
-            if !cur_tags.contains(tag) {
-                entries.push(DirectoryEntry {
-                    name: tag.to_os_string(),
-                    kind: FileType::Directory,
-                });
-            }
-        }
-
-        if !cur_tags.is_empty() {
-            for entry in &self.entries {
-                if entry.tags.is_superset(&cur_tags) {
-                    debug!("match {:?}", entry);
-                    entries.push(DirectoryEntry {
-                        name: OsString::from(
-                            format!("{:?} {:?}", entry.name, entry.absolute).replace('/', ":"),
-                        ),
-                        //name: entry.name.to_os_string(),
-                        kind: FileType::RegularFile,
-                    });
-                }
-            }
-        }
-        /*
-        let real = self.real_path(path);
-        // Consider using libc::readdir to prevent need for always stat-ing entries
-        let iter = match fs::read_dir(&real) {
-            Ok(iter) => iter,
-            Err(e) => return Err(e.raw_os_error().unwrap_or(ENOENT))
-        };
-        for entry in iter {
-            match entry {
-                Ok(entry) => {
-                    let real_path = entry.path();
-                    debug!("readdir: {:?} {:?}", real, real_path);
-                    let stat = match libc_wrapper::lstat(real_path.clone()) {
-                        Ok(stat) => stat,
-                        Err(e) => return Err(e.raw_os_error().unwrap_or(ENOENT))
-                    };
-                    let filetype = DecoFS::stat_to_filetype(&stat);
-
-                    entries.push(DirectoryEntry {
-                        name: real_path.file_name().unwrap().to_os_string(),
-                        kind: filetype,
-                    });
-                },
-                Err(e) => {
-                    error!("readdir: {:?}: {}", path, e);
-                    return Err(e.raw_os_error().unwrap_or(ENOENT));
-                }
-            }
-        }
-        */
+            .collect::<Vec<_>>();
         info!("entries: {:?}", entries);
         Ok(entries)
     }
@@ -305,15 +113,6 @@ impl FilesystemMT for TagFS {
             let size: usize = self.attrs.keys().map(|name| name.len()).sum();
             return Ok(Xattr::Size(size as u32));
         }
-        print!(
-            "{:?}",
-            self.attrs
-                .keys()
-                .map(|name| name.as_bytes())
-                .collect::<Vec<_>>()
-                .join(&0_u8)
-        );
-        //print!("{:?}", attrs.iter().flat_map(|attr| attr.as_bytes().to_vec().push(0_u8)).collect::<Vec<_>>());
         let mut data = self
             .attrs
             .keys()
@@ -359,6 +158,95 @@ impl FilesystemMT for TagFS {
             flags,
             position
         );
-        Err(libc::ENODATA)
+        let tag = match Self::tag_from_xattr_name(name) {
+            Some(tag) => tag,
+            None => return Err(libc::ENODATA),
+        };
+        self.vfs
+            .set_tag(path, tag)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
+        debug!("removexattr: {:?} {:?}", path, name);
+        let tag = match Self::tag_from_xattr_name(name) {
+            Some(tag) => tag,
+            None => return Err(libc::ENODATA),
+        };
+        self.vfs
+            .remove_tag(path, &tag)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn mkdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
+        debug!("mkdir: {:?}/{:?}", parent, name);
+        self.vfs
+            .add_empty_tag(name.to_os_string())
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        self.vfs
+            .attr(&parent.join(name))
+            .map(|attr| (TTL, Self::attr_to_fuse(attr)))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        debug!("rmdir: {:?}/{:?}", parent, name);
+        self.vfs
+            .remove_empty_tag(name)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn rename(
+        &self,
+        _req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        new_parent: &Path,
+        new_name: &OsStr,
+    ) -> ResultEmpty {
+        let path = parent.join(name);
+        debug!("rename: {:?} -> {:?}/{:?}", path, new_parent, new_name);
+        self.vfs
+            .rename(&path, new_parent, new_name)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        debug!("open: {:?} (flags = {:#o})", path, flags);
+        self.vfs
+            .open_handle(path, flags as i32)
+            .map(|fh| (fh, flags))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn read(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        debug!("read: {:?} {:#x} @ {:#x} ({} bytes)", path, fh, offset, size);
+        match self.vfs.read(fh, offset, size) {
+            Ok(buf) => callback(Ok(&buf)),
+            Err(e) => callback(Err(e.raw_os_error().unwrap_or(libc::EIO))),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> ResultEmpty {
+        debug!("release: {:?} {:#x}", path, fh);
+        self.vfs
+            .close(fh)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
     }
 }
@@ -0,0 +1,1170 @@
+//! The tag tree itself, independent of how it's served. `Vfs` owns the scanned
+//! entries and answers the questions any frontend needs to ask: what's in a tag
+//! directory, what are a node's attributes, and how to read a leaf file's bytes.
+//! `TagFS` (FUSE) and `WebDavFS` (WebDAV) are both thin adapters over this type.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component::Normal, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::libc_wrapper;
+
+/// Sidecar xattr on the backing file holding the full, comma-separated tag set, so
+/// tags added/removed at runtime survive a remount.
+const TAGS_SIDECAR_XATTR: &str = "user.tagfs.tags";
+
+/// A boolean query over a file's tag set, parsed from the path components used to
+/// navigate the tag tree. `And` is implicit between path components (`a/b`), `+`
+/// within a component is `Or` (`a+b`), and a leading `-` is `Not` (`-c`).
+#[derive(Debug, Clone)]
+enum TagQuery {
+    And(Vec<TagQuery>),
+    Or(Vec<TagQuery>),
+    Not(Box<TagQuery>),
+    Tag(OsString),
+}
+
+impl TagQuery {
+    fn eval(&self, tags: &HashSet<OsString>) -> bool {
+        match self {
+            TagQuery::And(queries) => queries.iter().all(|query| query.eval(tags)),
+            TagQuery::Or(queries) => queries.iter().any(|query| query.eval(tags)),
+            TagQuery::Not(query) => !query.eval(tags),
+            TagQuery::Tag(tag) => tags.contains(tag),
+        }
+    }
+
+    /// Parse a single path component, e.g. `a`, `-c`, or `a+b`.
+    fn parse(component: &OsStr) -> TagQuery {
+        let text = component.to_string_lossy();
+        let mut terms: Vec<TagQuery> = text
+            .split('+')
+            .map(|term| match term.strip_prefix('-') {
+                Some(excluded) => TagQuery::Not(Box::new(TagQuery::Tag(OsString::from(excluded)))),
+                None => TagQuery::Tag(OsString::from(term)),
+            })
+            .collect();
+        if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            TagQuery::Or(terms)
+        }
+    }
+
+    /// Fold every path component into the `And` of its parsed per-component query.
+    fn parse_path(path: &Path) -> TagQuery {
+        TagQuery::And(
+            path.components()
+                .filter_map(|c| match c {
+                    Normal(t) => Some(TagQuery::parse(t)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Whether `tag` can be added via `mkdir`/`setxattr` without becoming ambiguous
+/// with `TagQuery::parse`'s path grammar: a literal tag containing `+` would
+/// split into an `Or` of its parts, and one starting with `-` would parse as a
+/// `Not`, so a file carrying either could never be reached again by browsing its
+/// own tag directory.
+fn is_valid_tag(tag: &OsStr) -> bool {
+    let text = tag.to_string_lossy();
+    !text.contains('+') && !text.starts_with('-') && !text.is_empty()
+}
+
+/// The kind of a node in the tag tree, independent of any frontend's own file type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsKind {
+    Directory,
+    RegularFile,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    NamedPipe,
+    Socket,
+}
+
+fn mode_to_kind(mode: libc::mode_t) -> VfsKind {
+    match mode & libc::S_IFMT {
+        libc::S_IFDIR => VfsKind::Directory,
+        libc::S_IFREG => VfsKind::RegularFile,
+        libc::S_IFLNK => VfsKind::Symlink,
+        libc::S_IFBLK => VfsKind::BlockDevice,
+        libc::S_IFCHR => VfsKind::CharDevice,
+        libc::S_IFIFO => VfsKind::NamedPipe,
+        libc::S_IFSOCK => VfsKind::Socket,
+        _ => {
+            panic!("unknown file type");
+        }
+    }
+}
+
+/// The attributes of a node, in a form any frontend can translate into its own
+/// file-attribute type.
+#[derive(Debug, Clone)]
+pub struct VfsAttr {
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub kind: VfsKind,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+}
+
+fn stat_to_vfs_attr(stat: libc::stat) -> VfsAttr {
+    VfsAttr {
+        size: stat.st_size as u64,
+        blocks: stat.st_blocks as u64,
+        atime: SystemTime::UNIX_EPOCH
+            + Duration::from_secs(stat.st_atime as u64)
+            + Duration::from_nanos(stat.st_atime_nsec as u64),
+        mtime: SystemTime::UNIX_EPOCH
+            + Duration::from_secs(stat.st_mtime as u64)
+            + Duration::from_nanos(stat.st_mtime_nsec as u64),
+        ctime: SystemTime::UNIX_EPOCH
+            + Duration::from_secs(stat.st_ctime as u64)
+            + Duration::from_nanos(stat.st_ctime_nsec as u64),
+        kind: mode_to_kind(stat.st_mode),
+        perm: (stat.st_mode & 0o7777) as u16,
+        nlink: stat.st_nlink as u32,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        rdev: stat.st_rdev as u32,
+    }
+}
+
+/// The synthetic attributes of a tag directory, which has no backing inode.
+fn directory_attr() -> VfsAttr {
+    VfsAttr {
+        size: 0,
+        blocks: 0,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        kind: VfsKind::Directory,
+        perm: 0o0755,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+    }
+}
+
+/// Where a `TagFSEntry`'s bytes actually live.
+#[derive(Debug, Clone)]
+enum EntryBacking {
+    /// A real file on disk, backing a live directory scan.
+    Path(PathBuf),
+    /// A byte range inside a `*.tar` archive, backing an archive scan. Tags on
+    /// these entries are in-memory only: there's no writable backing file to
+    /// stash a sidecar xattr on.
+    Archive {
+        archive: Arc<PathBuf>,
+        offset: u64,
+        mtime: SystemTime,
+        mode: u32,
+    },
+}
+
+#[derive(Debug)]
+struct TagFSEntry {
+    name: OsString,
+    backing: EntryBacking,
+    size: u64,
+    tags: HashSet<OsString>,
+}
+
+/// Drop any tag that fails `is_valid_tag`, e.g. a real directory named `c++` or
+/// `-baz`: letting it through would list it as a tag directory in `readdir` that
+/// can never be entered again, since `TagQuery::parse` would read `+`/a leading
+/// `-` as query syntax rather than a literal tag and so never match it back.
+fn sanitize_tags(tags: HashSet<OsString>, source: &Path) -> HashSet<OsString> {
+    tags.into_iter()
+        .filter(|tag| {
+            let valid = is_valid_tag(tag);
+            if !valid {
+                warn!(
+                    "skipping tag {:?} derived from {}: would be misparsed as query syntax",
+                    tag,
+                    source.display()
+                );
+            }
+            valid
+        })
+        .collect()
+}
+
+/// A file's tags: whatever's in its `user.tagfs.tags` sidecar xattr if it has
+/// one, else the directory components `scan_path` was found under. `setxattr`/
+/// `removexattr` don't touch a file's mtime or inode, so this can't be skipped
+/// based on the catalog's cached identity check — it has to be read fresh on
+/// every scan, cache hit or not, to see tag edits made since the last one.
+fn resolve_tags(root: &str, scan_path: &Path, absolute: &Path) -> HashSet<OsString> {
+    let components: HashSet<_> = scan_path
+        .parent()
+        .unwrap()
+        .strip_prefix(root)
+        .unwrap()
+        .components()
+        .map(|comp| comp.as_os_str().to_owned())
+        .collect();
+    let tags = libc_wrapper::getxattr(absolute, TAGS_SIDECAR_XATTR)
+        .ok()
+        .map(|data| {
+            String::from_utf8_lossy(&data)
+                .split(',')
+                .filter(|tag| !tag.is_empty())
+                .map(OsString::from)
+                .collect()
+        })
+        .unwrap_or(components);
+    sanitize_tags(tags, scan_path)
+}
+
+impl TagFSEntry {
+    fn new(root: &str, entry: &walkdir::DirEntry, meta: &std::fs::Metadata) -> TagFSEntry {
+        let absolute = std::env::current_dir()
+            .unwrap()
+            .as_path()
+            .join(entry.path())
+            .canonicalize()
+            .unwrap();
+        let tags = resolve_tags(root, entry.path(), &absolute);
+        TagFSEntry {
+            name: entry.file_name().to_owned(),
+            backing: EntryBacking::Path(absolute),
+            size: meta.size(),
+            tags,
+        }
+    }
+
+    /// A human-readable description of where this entry's bytes live, for log
+    /// messages.
+    fn source(&self) -> String {
+        match &self.backing {
+            EntryBacking::Path(path) => path.display().to_string(),
+            EntryBacking::Archive { archive, offset, .. } => {
+                format!("{}@{}", archive.display(), offset)
+            }
+        }
+    }
+
+    fn stat(&self) -> io::Result<VfsAttr> {
+        match &self.backing {
+            EntryBacking::Path(path) => {
+                let stat = libc_wrapper::lstat(path)?;
+                Ok(stat_to_vfs_attr(stat))
+            }
+            EntryBacking::Archive { mtime, mode, .. } => Ok(VfsAttr {
+                size: self.size,
+                blocks: self.size.div_ceil(512),
+                atime: *mtime,
+                mtime: *mtime,
+                ctime: *mtime,
+                kind: VfsKind::RegularFile,
+                perm: (*mode & 0o7777) as u16,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+            }),
+        }
+    }
+
+    /// Write the current tag set to the sidecar xattr so it survives a remount.
+    /// A no-op for archive-backed entries.
+    fn persist_tags(&self) -> io::Result<()> {
+        let path = match &self.backing {
+            EntryBacking::Path(path) => path,
+            EntryBacking::Archive { .. } => return Ok(()),
+        };
+        if self.tags.is_empty() {
+            // Nothing left to persist: drop the sidecar entirely rather than
+            // leaving an empty value behind.
+            return match libc_wrapper::removexattr(path, TAGS_SIDECAR_XATTR) {
+                Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+                result => result,
+            };
+        }
+        let joined = self
+            .tags
+            .iter()
+            .map(|tag| tag.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(",");
+        libc_wrapper::setxattr(path, TAGS_SIDECAR_XATTR, joined.as_bytes())
+    }
+}
+
+/// A single entry returned by [`Vfs::readdir`].
+pub struct VfsDirEntry {
+    pub name: OsString,
+    pub kind: VfsKind,
+}
+
+/// An open file handle, opaque to callers, mapping back to the raw fd a read
+/// should come from and the offset its data starts at within that fd.
+#[derive(Debug)]
+struct OpenFile {
+    fd: i32,
+    base_offset: i64,
+}
+
+/// The mutable part of the tree: the scanned entries, the filesystem-wide tag
+/// index, and open file handles, held behind a single lock so tagging operations
+/// update both atomically.
+#[derive(Debug)]
+struct VfsState {
+    tags: HashSet<OsString>,
+    entries: Vec<TagFSEntry>,
+    open_files: HashMap<u64, OpenFile>,
+    next_fh: u64,
+}
+
+#[derive(Debug)]
+pub struct Vfs {
+    // Only read by `rescan`, which itself is only ever called by `watch::spawn`
+    // from the webdav/9p frontends (see the matching `cfg_attr` on `rescan`).
+    #[cfg_attr(
+        not(all(feature = "watch", any(feature = "webdav", feature = "9p"))),
+        allow(dead_code)
+    )]
+    root: String,
+    state: Mutex<VfsState>,
+}
+
+impl Vfs {
+    pub fn new(root: &str) -> Vfs {
+        let entries = Self::scan_root(root);
+        debug!("{:?}", entries);
+        let tags = entries
+            .iter()
+            .flat_map(|tag_entry| tag_entry.tags.clone())
+            .collect();
+        Vfs {
+            root: root.to_string(),
+            state: Mutex::new(VfsState {
+                tags,
+                entries,
+                open_files: HashMap::new(),
+                next_fh: 0,
+            }),
+        }
+    }
+
+    fn scan_root(root: &str) -> Vec<TagFSEntry> {
+        let path = Path::new(root);
+        let is_tar = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("tar"))
+            .unwrap_or(false);
+        if is_tar {
+            scan_archive(path)
+        } else {
+            scan_incremental(root)
+        }
+    }
+
+    /// Re-derive the entries and tag index from scratch, replacing the in-memory
+    /// state. Used to pick up changes made directly on the backing directory or
+    /// archive while mounted, e.g. from a filesystem watcher.
+    ///
+    /// Only called from `watch::spawn`, which itself is only wired up from the
+    /// webdav/9p frontends in `main`, so it's dead unless `watch` is enabled
+    /// together with at least one of them.
+    #[cfg_attr(
+        not(all(feature = "watch", any(feature = "webdav", feature = "9p"))),
+        allow(dead_code)
+    )]
+    pub fn rescan(&self) {
+        let entries = Self::scan_root(&self.root);
+        let tags = entries
+            .iter()
+            .flat_map(|tag_entry| tag_entry.tags.clone())
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        state.entries = entries;
+        state.tags = tags;
+    }
+
+    /// The literal path components of `path`, treated as a set of bare tag names
+    /// (ignoring the `+`/`-` query syntax), e.g. for detecting tags already named
+    /// on the path.
+    fn path_tags(path: &Path) -> HashSet<OsString> {
+        path.components()
+            .filter_map(|c| match c {
+                Normal(t) => Some(t.to_os_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Entries whose tag set satisfies `query`, i.e. the files that would be listed
+    /// under the directory formed by `query`.
+    fn matching_entries<'a>(entries: &'a [TagFSEntry], query: &TagQuery) -> Vec<&'a TagFSEntry> {
+        entries
+            .iter()
+            .filter(|entry| query.eval(&entry.tags))
+            .collect()
+    }
+
+    /// Synthesize the stable leaf name each of `entries` is exposed as in
+    /// `readdir`, disambiguating files that share a name under the same tag set by
+    /// suffixing a deterministic index onto every name after the first.
+    fn leaf_names(entries: &[&TagFSEntry]) -> Vec<OsString> {
+        let mut counts: HashMap<&OsStr, usize> = HashMap::new();
+        for entry in entries {
+            *counts.entry(entry.name.as_os_str()).or_insert(0) += 1;
+        }
+        // Every literal name present in this listing, so a generated suffix never
+        // shadows a real file that happens to already be named e.g. `foo.0`.
+        let literal_names: HashSet<&OsStr> = counts.keys().copied().collect();
+        let mut seen: HashMap<&OsStr, usize> = HashMap::new();
+        entries
+            .iter()
+            .map(|entry| {
+                let name = entry.name.as_os_str();
+                if counts[name] <= 1 {
+                    return name.to_os_string();
+                }
+                let index = seen.entry(name).or_insert(0);
+                loop {
+                    let candidate = OsString::from(format!("{}.{}", name.to_string_lossy(), index));
+                    *index += 1;
+                    if !literal_names.contains(candidate.as_os_str()) {
+                        return candidate;
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Resolve a full path to the index, within `entries`, of the `TagFSEntry` it
+    /// names, splitting off the final component as the leaf name and treating the
+    /// rest as the tag query. Returns `None` for paths that name a tag directory
+    /// rather than a file.
+    fn lookup_index(entries: &[TagFSEntry], path: &Path) -> Option<usize> {
+        let mut components: Vec<OsString> = path
+            .components()
+            .filter_map(|c| match c {
+                Normal(t) => Some(t.to_os_string()),
+                _ => None,
+            })
+            .collect();
+        let leaf = components.pop()?;
+        let query = TagQuery::And(components.iter().map(|c| TagQuery::parse(c)).collect());
+        let indices: Vec<usize> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| query.eval(&entry.tags))
+            .map(|(index, _)| index)
+            .collect();
+        let refs: Vec<&TagFSEntry> = indices.iter().map(|&index| &entries[index]).collect();
+        let names = Self::leaf_names(&refs);
+        indices
+            .into_iter()
+            .zip(names)
+            .find(|(_, name)| *name == leaf)
+            .map(|(index, _)| index)
+    }
+
+    fn lookup<'a>(entries: &'a [TagFSEntry], path: &Path) -> Option<&'a TagFSEntry> {
+        Self::lookup_index(entries, path).map(|index| &entries[index])
+    }
+
+    /// The attributes of the node named by `path`: the real `lstat` of the backing
+    /// file for a leaf, or synthetic directory attributes for a tag path.
+    pub fn attr(&self, path: &Path) -> io::Result<VfsAttr> {
+        let state = self.state.lock().unwrap();
+        match Self::lookup(&state.entries, path) {
+            Some(entry) => entry.stat(),
+            None => Ok(directory_attr()),
+        }
+    }
+
+    /// The directory listing for a tag path: unused tags that would still yield a
+    /// non-empty result given the accumulated query, plus any matching files.
+    pub fn readdir(&self, path: &Path) -> Vec<VfsDirEntry> {
+        let cur_tags = Self::path_tags(path);
+        let query = TagQuery::parse_path(path);
+
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<VfsDirEntry> = vec![];
+        for tag in &state.tags {
+            if cur_tags.contains(tag) {
+                continue;
+            }
+            let narrowed = TagQuery::And(vec![query.clone(), TagQuery::Tag(tag.clone())]);
+            if Self::matching_entries(&state.entries, &narrowed).is_empty() {
+                continue;
+            }
+            entries.push(VfsDirEntry {
+                name: tag.to_os_string(),
+                kind: VfsKind::Directory,
+            });
+        }
+
+        if !cur_tags.is_empty() {
+            let matches = Self::matching_entries(&state.entries, &query);
+            for (entry, name) in matches.iter().zip(Self::leaf_names(&matches)) {
+                debug!("match {:?} as {:?}", entry, name);
+                entries.push(VfsDirEntry {
+                    name,
+                    kind: VfsKind::RegularFile,
+                });
+            }
+        }
+        entries
+    }
+
+    /// Open the backing file for the leaf named by `path`, returning an opaque
+    /// handle good for [`Vfs::read`] until it's passed to [`Vfs::close`]. For a
+    /// `Path`-backed entry this is a fresh fd on the real file; for an
+    /// `Archive`-backed entry it's a fresh fd on the archive itself, with reads
+    /// translated to start at the entry's offset within it.
+    pub fn open_handle(&self, path: &Path, flags: i32) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let open_file = match Self::lookup(&state.entries, path) {
+            Some(entry) => match &entry.backing {
+                EntryBacking::Path(path) => OpenFile {
+                    fd: libc_wrapper::open(path, flags)?,
+                    base_offset: 0,
+                },
+                EntryBacking::Archive { archive, offset, .. } => OpenFile {
+                    fd: libc_wrapper::open(archive.as_path(), flags)?,
+                    base_offset: *offset as i64,
+                },
+            },
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+        let fh = state.next_fh;
+        state.next_fh += 1;
+        state.open_files.insert(fh, open_file);
+        Ok(fh)
+    }
+
+    /// Read `size` bytes at `offset` from a handle previously returned by
+    /// [`Vfs::open_handle`].
+    pub fn read(&self, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let open_file = state
+            .open_files
+            .get(&fh)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        let mut buf = vec![0_u8; size as usize];
+        let n = libc_wrapper::pread(open_file.fd, &mut buf, open_file.base_offset + offset as i64)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// Close a handle previously returned by [`Vfs::open_handle`].
+    pub fn close(&self, fh: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_files.remove(&fh) {
+            Some(open_file) => libc_wrapper::close(open_file.fd),
+            None => Err(io::Error::from_raw_os_error(libc::EBADF)),
+        }
+    }
+
+    /// Read a byte range from the leaf named by `path`, without the caller having
+    /// to manage an open file handle.
+    ///
+    /// Only called from the webdav/9p frontends, so a default-features build
+    /// never calls it.
+    #[cfg_attr(not(any(feature = "webdav", feature = "9p")), allow(dead_code))]
+    pub fn read_at(&self, path: &Path, offset: u64, len: u32) -> io::Result<Vec<u8>> {
+        let fh = self.open_handle(path, libc::O_RDONLY)?;
+        let result = self.read(fh, offset, len);
+        let _ = self.close(fh);
+        result
+    }
+
+    /// Add `tag` to the leaf named by `path`, the effect of `setxattr` on
+    /// `user.tagfs.tag.<tag>`.
+    pub fn set_tag(&self, path: &Path, tag: OsString) -> io::Result<()> {
+        if !is_valid_tag(&tag) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let mut state = self.state.lock().unwrap();
+        let index = Self::lookup_index(&state.entries, path)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        state.entries[index].tags.insert(tag.clone());
+        if let Err(e) = state.entries[index].persist_tags() {
+            warn!(
+                "failed to persist tags for {}: {}",
+                state.entries[index].source(),
+                e
+            );
+        }
+        state.tags.insert(tag);
+        Ok(())
+    }
+
+    /// Remove `tag` from the leaf named by `path`, the effect of `removexattr` on
+    /// `user.tagfs.tag.<tag>`.
+    pub fn remove_tag(&self, path: &Path, tag: &OsStr) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let index = Self::lookup_index(&state.entries, path)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        state.entries[index].tags.remove(tag);
+        if let Err(e) = state.entries[index].persist_tags() {
+            warn!(
+                "failed to persist tags for {}: {}",
+                state.entries[index].source(),
+                e
+            );
+        }
+        if !state.entries.iter().any(|entry| entry.tags.contains(tag)) {
+            state.tags.remove(tag);
+        }
+        Ok(())
+    }
+
+    /// Register a tag with no entries yet, the effect of `mkdir` on a tag name.
+    pub fn add_empty_tag(&self, tag: OsString) -> io::Result<()> {
+        if !is_valid_tag(&tag) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.state.lock().unwrap().tags.insert(tag);
+        Ok(())
+    }
+
+    /// Drop a tag with no entries left, the effect of `rmdir` on a tag name. Fails
+    /// like a non-empty directory would if any entry still carries the tag.
+    pub fn remove_empty_tag(&self, tag: &OsStr) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.iter().any(|entry| entry.tags.contains(tag)) {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        state.tags.remove(tag);
+        Ok(())
+    }
+
+    /// Move the leaf named by `path` into the tag directory `new_parent` under
+    /// `new_name`: filing a file into a tag directory tags it, and taking it out
+    /// of one untags it.
+    pub fn rename(&self, path: &Path, new_parent: &Path, new_name: &OsStr) -> io::Result<()> {
+        let dest_tags = Self::path_tags(new_parent);
+        if dest_tags.iter().any(|tag| !is_valid_tag(tag)) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let index = Self::lookup_index(&state.entries, path)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+
+        let source_tags = Self::path_tags(path.parent().unwrap_or_else(|| Path::new("")));
+        for tag in source_tags.difference(&dest_tags) {
+            state.entries[index].tags.remove(tag);
+        }
+        for tag in &dest_tags {
+            state.entries[index].tags.insert(tag.clone());
+        }
+        state.entries[index].name = new_name.to_os_string();
+
+        if let Err(e) = state.entries[index].persist_tags() {
+            warn!(
+                "failed to persist tags for {}: {}",
+                state.entries[index].source(),
+                e
+            );
+        }
+        state.tags.extend(dest_tags);
+        Ok(())
+    }
+}
+
+/// Format of the on-disk catalog; bumped whenever `CatalogEntry`'s shape changes,
+/// so an old catalog is rejected (triggering a full rescan) rather than misread.
+const CATALOG_VERSION: u32 = 1;
+
+/// Catalog file dropped at the root of a scanned directory.
+pub(crate) const CATALOG_FILE: &str = ".tagfs.catalog";
+
+/// A cached `TagFSEntry`, plus enough of the backing file's identity (mtime,
+/// inode) to tell whether it needs re-reading on the next scan.
+#[derive(Debug, Serialize, Deserialize)]
+struct CatalogEntry {
+    /// The path as seen by `WalkDir` for a given `root`, used to match this
+    /// entry up against a future scan.
+    scan_path: PathBuf,
+    /// The canonicalized path the entry's `EntryBacking::Path` was built from.
+    path: PathBuf,
+    name: String,
+    size: u64,
+    mtime: i64,
+    ino: u64,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Catalog {
+    version: u32,
+    root: String,
+    entries: Vec<CatalogEntry>,
+}
+
+/// Load and validate the catalog left by a previous scan of `root`. Returns
+/// `None` if there is no catalog, it can't be parsed, or its format version or
+/// root path don't match, so the caller falls back to treating every file as new.
+fn load_catalog(root: &str) -> Option<Catalog> {
+    let data = fs::read(Path::new(root).join(CATALOG_FILE)).ok()?;
+    let catalog: Catalog = serde_json::from_slice(&data).ok()?;
+    if catalog.version != CATALOG_VERSION || catalog.root != root {
+        return None;
+    }
+    Some(catalog)
+}
+
+fn save_catalog(root: &str, entries: Vec<CatalogEntry>) {
+    let catalog = Catalog {
+        version: CATALOG_VERSION,
+        root: root.to_string(),
+        entries,
+    };
+    let path = Path::new(root).join(CATALOG_FILE);
+    match serde_json::to_vec(&catalog) {
+        Ok(data) => {
+            if let Err(e) = fs::write(&path, data) {
+                warn!("failed to write catalog {}: {}", path.display(), e);
+            }
+        }
+        Err(e) => warn!("failed to serialize catalog: {}", e),
+    }
+}
+
+/// Walk `root`, reusing the catalog left by a previous run: an entry whose
+/// backing file's mtime and inode haven't changed since the catalog was written
+/// skips the canonicalize `TagFSEntry::new` would otherwise do, reusing the
+/// cached path instead, but its tags are still re-read from the sidecar xattr
+/// every time, since tagging doesn't touch mtime/inode. New or changed files are
+/// scanned as usual, and files no longer present simply aren't carried forward.
+/// The refreshed catalog is written back out for the next startup.
+fn scan_incremental(root: &str) -> Vec<TagFSEntry> {
+    let cached: HashMap<PathBuf, CatalogEntry> = load_catalog(root)
+        .map(|catalog| {
+            catalog
+                .entries
+                .into_iter()
+                .map(|entry| (entry.scan_path.clone(), entry))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut catalog_entries = Vec::new();
+    let entries: Vec<TagFSEntry> = WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            if entry.file_name() == OsStr::new(CATALOG_FILE) {
+                return None;
+            }
+            let meta = fs::metadata(entry.path()).ok()?;
+            if !meta.is_file() || entry.path().parent().is_none() {
+                return None;
+            }
+            let scan_path = entry.path().to_path_buf();
+            let tag_entry = match cached.get(&scan_path) {
+                Some(cached_entry)
+                    if cached_entry.mtime == meta.mtime() && cached_entry.ino == meta.ino() =>
+                {
+                    // The cached identity (mtime/inode) only tells us the file's
+                    // *content* hasn't changed; tag edits go through setxattr/
+                    // removexattr, which touch neither, so the tag set itself
+                    // still has to be re-read rather than trusted from the catalog.
+                    TagFSEntry {
+                        name: entry.file_name().to_owned(),
+                        backing: EntryBacking::Path(cached_entry.path.clone()),
+                        size: cached_entry.size,
+                        tags: resolve_tags(root, &scan_path, &cached_entry.path),
+                    }
+                }
+                _ => TagFSEntry::new(root, &entry, &meta),
+            };
+            catalog_entries.push(CatalogEntry {
+                scan_path,
+                path: match &tag_entry.backing {
+                    EntryBacking::Path(path) => path.clone(),
+                    EntryBacking::Archive { .. } => {
+                        unreachable!("a directory scan never produces archive-backed entries")
+                    }
+                },
+                name: tag_entry.name.to_string_lossy().into_owned(),
+                size: tag_entry.size,
+                mtime: meta.mtime(),
+                ino: meta.ino(),
+                tags: tag_entry
+                    .tags
+                    .iter()
+                    .map(|tag| tag.to_string_lossy().into_owned())
+                    .collect(),
+            });
+            Some(tag_entry)
+        })
+        .collect();
+
+    save_catalog(root, catalog_entries);
+    entries
+}
+
+/// Prefix under which PAX extended header records are surfaced as extra tags,
+/// e.g. a `SCHILY.xattr.user.tagfs.tag.foo` PAX record tags the entry `foo`.
+const PAX_TAG_PREFIX: &str = "SCHILY.xattr.user.tagfs.tag.";
+
+/// Scan a `*.tar` archive, exposing each regular file entry's path components as
+/// tags in the same way [`scan`] does for a live directory, plus any extra tags
+/// stashed in PAX extended header records. The archive's own path components are
+/// resolved transparently for GNU/PAX long names by the `tar` crate.
+fn scan_archive(path: &Path) -> Vec<TagFSEntry> {
+    let archive = Arc::new(path.to_path_buf());
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("failed to open archive {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    let mtime = file
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut tar = tar::Archive::new(file);
+    let entries = match tar.entries_with_seek() {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("failed to read archive {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.header().entry_type().is_file())
+        .filter_map(|mut entry| {
+            let entry_path = entry.path().ok()?.into_owned();
+            let mut tags: HashSet<OsString> = entry_path
+                .parent()?
+                .components()
+                .map(|comp| comp.as_os_str().to_owned())
+                .collect();
+            if let Ok(Some(extensions)) = entry.pax_extensions() {
+                for record in extensions.flatten() {
+                    if let Some(tag) = record
+                        .key()
+                        .ok()
+                        .and_then(|key| key.strip_prefix(PAX_TAG_PREFIX))
+                    {
+                        tags.insert(OsString::from(tag));
+                    }
+                }
+            }
+            let name = entry_path.file_name()?.to_owned();
+            let size = entry.header().size().unwrap_or(0);
+            let offset = entry.raw_file_position();
+            Some(TagFSEntry {
+                name,
+                backing: EntryBacking::Archive {
+                    archive: archive.clone(),
+                    offset,
+                    mtime,
+                    mode: entry.header().mode().unwrap_or(0o0644),
+                },
+                size,
+                tags: sanitize_tags(tags, &entry_path),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(names: &[&str]) -> HashSet<OsString> {
+        names.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn parses_plain_tag() {
+        let query = TagQuery::parse(OsStr::new("foo"));
+        assert!(query.eval(&tags(&["foo"])));
+        assert!(!query.eval(&tags(&["bar"])));
+    }
+
+    #[test]
+    fn parses_negated_tag() {
+        let query = TagQuery::parse(OsStr::new("-foo"));
+        assert!(query.eval(&tags(&["bar"])));
+        assert!(!query.eval(&tags(&["foo"])));
+    }
+
+    #[test]
+    fn parses_or_of_tags() {
+        let query = TagQuery::parse(OsStr::new("foo+bar"));
+        assert!(query.eval(&tags(&["foo"])));
+        assert!(query.eval(&tags(&["bar"])));
+        assert!(!query.eval(&tags(&["baz"])));
+    }
+
+    #[test]
+    fn parse_path_ands_components() {
+        let query = TagQuery::parse_path(Path::new("/foo/bar"));
+        assert!(query.eval(&tags(&["foo", "bar"])));
+        assert!(!query.eval(&tags(&["foo"])));
+    }
+
+    fn entry(name: &str, tags: &[&str]) -> TagFSEntry {
+        TagFSEntry {
+            name: OsString::from(name),
+            backing: EntryBacking::Path(PathBuf::from(format!("/nonexistent/{name}"))),
+            size: 0,
+            tags: tags.iter().map(OsString::from).collect(),
+        }
+    }
+
+    #[test]
+    fn leaf_names_disambiguates_duplicates() {
+        let entries = [entry("foo", &["a"]), entry("foo", &["a"])];
+        let refs: Vec<&TagFSEntry> = entries.iter().collect();
+        let names = Vfs::leaf_names(&refs);
+        assert_eq!(names, vec![OsString::from("foo.0"), OsString::from("foo.1")]);
+    }
+
+    #[test]
+    fn leaf_names_skips_suffix_colliding_with_a_real_name() {
+        // A third entry is already literally named `foo.0`; the two `foo`s must
+        // not be suffixed into colliding with it.
+        let entries = [
+            entry("foo", &["a"]),
+            entry("foo", &["a"]),
+            entry("foo.0", &["a"]),
+        ];
+        let refs: Vec<&TagFSEntry> = entries.iter().collect();
+        let names = Vfs::leaf_names(&refs);
+        assert_eq!(names[2], OsString::from("foo.0"));
+        assert_ne!(names[0], OsString::from("foo.0"));
+        assert_ne!(names[1], OsString::from("foo.0"));
+        assert_ne!(names[0], names[1]);
+    }
+
+    #[test]
+    fn lookup_index_resolves_disambiguated_name() {
+        let entries = [entry("foo", &["a"]), entry("foo", &["a"])];
+        assert_eq!(Vfs::lookup_index(&entries, Path::new("/a/foo.0")), Some(0));
+        assert_eq!(Vfs::lookup_index(&entries, Path::new("/a/foo.1")), Some(1));
+    }
+
+    #[test]
+    fn sanitize_tags_drops_names_query_parsing_would_misread() {
+        let sanitized = sanitize_tags(tags(&["c++", "-baz", "", "fine"]), Path::new("/src"));
+        assert_eq!(sanitized, tags(&["fine"]));
+    }
+
+    #[test]
+    fn resolve_tags_skips_a_real_directory_name_that_is_not_a_valid_tag() {
+        // A directory like `c++` would be listed by `readdir` as a tag directory
+        // but could never be entered again, since `TagQuery::parse("c++")` reads
+        // `+` as `Or` rather than a literal tag: the scan must drop it rather
+        // than hand back a tag nothing can ever query back in.
+        let root = temp_root("invalid-dir-tag");
+        let subdir = root.join("c++");
+        fs::create_dir_all(&subdir).unwrap();
+        let leaf = subdir.join("leaf.txt");
+        fs::write(&leaf, b"hi").unwrap();
+
+        let root_str = root.to_str().unwrap();
+        let resolved = resolve_tags(root_str, &leaf, &leaf);
+        assert!(!resolved.contains(OsStr::new("c++")));
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn open_handle_read_close_round_trips_a_real_file() {
+        let path = temp_root("open-read").join(format!("leaf-{}", std::process::id()));
+        fs::write(&path, b"hello tagfs").unwrap();
+        let vfs = vfs_with_entries(vec![TagFSEntry {
+            name: OsString::from("leaf"),
+            backing: EntryBacking::Path(path.clone()),
+            size: 11,
+            tags: tags(&["a"]),
+        }]);
+
+        let fh = vfs.open_handle(Path::new("/a/leaf"), libc::O_RDONLY).unwrap();
+        let data = vfs.read(fh, 0, 64).unwrap();
+        assert_eq!(data, b"hello tagfs");
+
+        let tail = vfs.read(fh, 6, 64).unwrap();
+        assert_eq!(tail, b"tagfs");
+
+        vfs.close(fh).unwrap();
+        assert_eq!(
+            vfs.read(fh, 0, 1).unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+
+        fs::remove_file(&path).ok();
+    }
+
+    /// A fresh, per-test directory under the OS temp dir, so catalog fixture files
+    /// from different tests can't collide.
+    fn temp_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tagfs-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_catalog(root: &Path, version: u32, catalog_root: &str) {
+        let catalog = Catalog {
+            version,
+            root: catalog_root.to_string(),
+            entries: Vec::new(),
+        };
+        fs::write(
+            root.join(CATALOG_FILE),
+            serde_json::to_vec(&catalog).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn load_catalog_rejects_version_mismatch() {
+        let root = temp_root("version-mismatch");
+        let root_str = root.to_str().unwrap();
+        write_catalog(&root, CATALOG_VERSION + 1, root_str);
+        assert!(load_catalog(root_str).is_none());
+    }
+
+    #[test]
+    fn load_catalog_rejects_root_mismatch() {
+        let root = temp_root("root-mismatch");
+        let root_str = root.to_str().unwrap();
+        write_catalog(&root, CATALOG_VERSION, "/some/other/root");
+        assert!(load_catalog(root_str).is_none());
+    }
+
+    #[test]
+    fn load_catalog_accepts_matching_version_and_root() {
+        let root = temp_root("matching");
+        let root_str = root.to_str().unwrap();
+        write_catalog(&root, CATALOG_VERSION, root_str);
+        assert!(load_catalog(root_str).is_some());
+    }
+
+    #[test]
+    fn is_valid_tag_rejects_query_syntax_and_empty() {
+        assert!(is_valid_tag(OsStr::new("plain")));
+        assert!(!is_valid_tag(OsStr::new("a+b")));
+        assert!(!is_valid_tag(OsStr::new("-c")));
+        assert!(!is_valid_tag(OsStr::new("")));
+    }
+
+    /// A fresh file under the OS temp dir, for tests that exercise real xattr
+    /// persistence against a backing file.
+    fn temp_file(name: &str) -> PathBuf {
+        let path = temp_root("tags").join(format!("{}-{}", name, std::process::id()));
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    fn vfs_with_entries(entries: Vec<TagFSEntry>) -> Vfs {
+        let tags = entries
+            .iter()
+            .flat_map(|entry| entry.tags.clone())
+            .collect();
+        Vfs {
+            root: String::new(),
+            state: Mutex::new(VfsState {
+                tags,
+                entries,
+                open_files: HashMap::new(),
+                next_fh: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn set_tag_persists_and_round_trips_the_sidecar_xattr() {
+        let path = temp_file("set-tag");
+        let vfs = vfs_with_entries(vec![TagFSEntry {
+            name: OsString::from("leaf"),
+            backing: EntryBacking::Path(path.clone()),
+            size: 0,
+            tags: tags(&[]),
+        }]);
+
+        vfs.set_tag(Path::new("/leaf"), OsString::from("red")).unwrap();
+        let stored = libc_wrapper::getxattr(&path, TAGS_SIDECAR_XATTR).unwrap();
+        assert_eq!(stored, b"red");
+
+        // Removing the last tag should drop the sidecar entirely rather than
+        // leaving an empty value behind.
+        vfs.remove_tag(Path::new("/red/leaf"), OsStr::new("red"))
+            .unwrap();
+        let after_removal = libc_wrapper::getxattr(&path, TAGS_SIDECAR_XATTR);
+        assert_eq!(
+            after_removal.unwrap_err().raw_os_error(),
+            Some(libc::ENODATA)
+        );
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn remove_empty_tag_fails_while_an_entry_still_carries_it() {
+        let vfs = vfs_with_entries(vec![entry("leaf", &["a"])]);
+        let err = vfs.remove_empty_tag(OsStr::new("a")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+    }
+
+    #[test]
+    fn remove_empty_tag_succeeds_once_unused() {
+        let vfs = vfs_with_entries(vec![entry("leaf", &["a"])]);
+        vfs.add_empty_tag(OsString::from("b")).unwrap();
+        assert!(vfs.remove_empty_tag(OsStr::new("b")).is_ok());
+    }
+
+    #[test]
+    fn rename_transfers_tags_between_old_and_new_parent() {
+        let path = temp_file("rename");
+        let vfs = vfs_with_entries(vec![TagFSEntry {
+            name: OsString::from("leaf"),
+            backing: EntryBacking::Path(path.clone()),
+            size: 0,
+            tags: tags(&["a"]),
+        }]);
+
+        vfs.rename(Path::new("/a/leaf"), Path::new("/b"), OsStr::new("leaf"))
+            .unwrap();
+
+        let state = vfs.state.lock().unwrap();
+        assert_eq!(state.entries[0].tags, tags(&["b"]));
+        drop(state);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rename_rejects_an_invalid_destination_tag() {
+        let vfs = vfs_with_entries(vec![entry("leaf", &["a"])]);
+        let err = vfs
+            .rename(Path::new("/a/leaf"), Path::new("/-b"), OsStr::new("leaf"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+}
@@ -0,0 +1,50 @@
+//! Optional live-update: watch the root directory for filesystem events and
+//! rescan the tag tree when something changes, so `entries` and `self.tags` stay
+//! current without requiring a remount.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::vfs::{Vfs, CATALOG_FILE};
+
+/// Spawn a background thread that rescans `vfs` whenever `root` changes on disk.
+/// Errors setting up the watch are logged and otherwise ignored: the mount still
+/// works, it just won't pick up out-of-band changes until the next remount.
+///
+/// Only called from `main`'s webdav/9p frontends, so it's dead if neither is
+/// enabled alongside this feature.
+#[cfg_attr(not(any(feature = "webdav", feature = "9p")), allow(dead_code))]
+pub fn spawn(vfs: Arc<Vfs>, root: &str) {
+    let root = root.to_string();
+    std::thread::spawn(move || {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("watch: failed to create watcher for {}: {}", root, e);
+                return;
+            }
+        };
+        if let Err(e) = watcher.watch(Path::new(&root), RecursiveMode::Recursive) {
+            warn!("watch: failed to watch {}: {}", root, e);
+            return;
+        }
+        let catalog_path = Path::new(&root).join(CATALOG_FILE);
+        for event in rx {
+            // Rescanning writes the catalog back out inside `root`, which would
+            // otherwise fire another event and rescan forever; ignore any event
+            // that's purely about the catalog file itself.
+            let event = match event {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            if event.paths.iter().all(|path| *path == catalog_path) {
+                continue;
+            }
+            debug!("watch: {} changed, rescanning", root);
+            vfs.rescan();
+        }
+    });
+}
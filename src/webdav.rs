@@ -0,0 +1,225 @@
+//! Serve the tag tree over WebDAV, so it's browsable from a web client or mapped
+//! as a network drive without a FUSE kernel module. `WebDavFS` is a thin adapter
+//! over `Vfs`, the same tag-navigation core the FUSE frontend uses.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use dav_server::davpath::DavPath;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+    OpenOptions, ReadDirMeta,
+};
+use dav_server::DavHandler;
+use futures_util::stream;
+
+use crate::vfs::{Vfs, VfsAttr, VfsKind};
+
+fn to_fs_error(e: io::Error) -> FsError {
+    match e.raw_os_error() {
+        Some(libc::ENOENT) => FsError::NotFound,
+        Some(libc::EEXIST) => FsError::Exists,
+        _ => FsError::GeneralFailure,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TagFSMetaData {
+    attr: VfsAttr,
+}
+
+impl DavMetaData for TagFSMetaData {
+    fn len(&self) -> u64 {
+        self.attr.size
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.attr.mtime)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr.kind == VfsKind::Directory
+    }
+
+    fn is_file(&self) -> bool {
+        self.attr.kind == VfsKind::RegularFile
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.attr.kind == VfsKind::Symlink
+    }
+}
+
+#[derive(Debug)]
+struct TagFSDirEntry {
+    name: Vec<u8>,
+    attr: VfsAttr,
+}
+
+impl DavDirEntry for TagFSDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+
+    fn metadata<'a>(&'a self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let attr = self.attr.clone();
+        Box::pin(async move { Ok(Box::new(TagFSMetaData { attr }) as Box<dyn DavMetaData>) })
+    }
+}
+
+/// A leaf file opened for reading; WebDAV range requests are served straight from
+/// `Vfs::read_at`, so no file handle needs to be kept open between calls. `pos`
+/// tracks how far into the file the next `read_bytes` should continue from,
+/// since `dav-server` issues several reads per file rather than one.
+#[derive(Debug)]
+struct TagFSFile {
+    vfs: Arc<Vfs>,
+    path: PathBuf,
+    attr: VfsAttr,
+    pos: u64,
+}
+
+impl DavFile for TagFSFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let attr = self.attr.clone();
+        Box::pin(async move { Ok(Box::new(TagFSMetaData { attr }) as Box<dyn DavMetaData>) })
+    }
+
+    fn write_bytes<'a>(&'a mut self, _buf: Bytes) -> FsFuture<'a, ()> {
+        Box::pin(async move { Err(FsError::NotImplemented) })
+    }
+
+    fn write_buf<'a>(&'a mut self, _buf: Box<dyn bytes::Buf + Send>) -> FsFuture<'a, ()> {
+        Box::pin(async move { Err(FsError::NotImplemented) })
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        Box::pin(async move {
+            let data = self
+                .vfs
+                .read_at(&self.path, self.pos, count as u32)
+                .map_err(to_fs_error)?;
+            self.pos += data.len() as u64;
+            Ok(Bytes::from(data))
+        })
+    }
+
+    fn seek<'a>(&'a mut self, pos: io::SeekFrom) -> FsFuture<'a, u64> {
+        Box::pin(async move {
+            let new_pos = match pos {
+                io::SeekFrom::Start(offset) => offset as i64,
+                io::SeekFrom::Current(offset) => self.pos as i64 + offset,
+                io::SeekFrom::End(offset) => self.attr.size as i64 + offset,
+            };
+            let new_pos = u64::try_from(new_pos).map_err(|_| FsError::GeneralFailure)?;
+            self.pos = new_pos;
+            Ok(new_pos)
+        })
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebDavFS {
+    vfs: Arc<Vfs>,
+}
+
+impl WebDavFS {
+    pub fn new(vfs: Arc<Vfs>) -> WebDavFS {
+        WebDavFS { vfs }
+    }
+
+    fn to_path(dav_path: &DavPath) -> PathBuf {
+        Path::new("/").join(dav_path.as_rel_ospath())
+    }
+}
+
+impl DavFileSystem for WebDavFS {
+    fn open<'a>(&'a self, path: &'a DavPath, _options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        Box::pin(async move {
+            let path = Self::to_path(path);
+            let attr = self.vfs.attr(&path).map_err(to_fs_error)?;
+            Ok(Box::new(TagFSFile {
+                vfs: self.vfs.clone(),
+                path,
+                attr,
+                pos: 0,
+            }) as Box<dyn DavFile>)
+        })
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        Box::pin(async move {
+            let path = Self::to_path(path);
+            let entries = self
+                .vfs
+                .readdir(&path)
+                .into_iter()
+                .map(|entry| {
+                    let attr = self.vfs.attr(&path.join(&entry.name)).unwrap_or(VfsAttr {
+                        size: 0,
+                        blocks: 0,
+                        atime: SystemTime::UNIX_EPOCH,
+                        mtime: SystemTime::UNIX_EPOCH,
+                        ctime: SystemTime::UNIX_EPOCH,
+                        kind: entry.kind,
+                        perm: 0o0644,
+                        nlink: 1,
+                        uid: 0,
+                        gid: 0,
+                        rdev: 0,
+                    });
+                    Box::new(TagFSDirEntry {
+                        name: entry.name.to_string_lossy().into_owned().into_bytes(),
+                        attr,
+                    }) as Box<dyn DavDirEntry>
+                })
+                .collect::<Vec<_>>();
+            Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        Box::pin(async move {
+            let attr = self.vfs.attr(&Self::to_path(path)).map_err(to_fs_error)?;
+            Ok(Box::new(TagFSMetaData { attr }) as Box<dyn DavMetaData>)
+        })
+    }
+}
+
+/// Serve `vfs` over WebDAV at `addr`, e.g. `127.0.0.1:4918`. Blocks the calling
+/// thread for the lifetime of the server.
+pub fn serve(vfs: Arc<Vfs>, addr: &str) -> io::Result<()> {
+    let addr = addr
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let handler = DavHandler::builder()
+        .filesystem(Box::new(WebDavFS::new(vfs)))
+        .build_handler();
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async move {
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                    let handler = handler.clone();
+                    async move { Ok::<_, std::convert::Infallible>(handler.handle(req).await) }
+                }))
+            }
+        });
+        info!("webdav: listening on {}", addr);
+        hyper::Server::bind(&addr).serve(make_service).await
+    })
+    .map_err(io::Error::other)
+}